@@ -1,12 +1,14 @@
 #[macro_use]
 extern crate failure;
 extern crate geo;
+extern crate geojson;
 extern crate gst;
 #[macro_use]
 extern crate log;
 extern crate ordered_float;
 extern crate osm_boundaries_utils;
 extern crate osmpbfreader;
+extern crate rayon;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
@@ -19,7 +21,10 @@ extern crate lazy_static;
 pub mod cosmogony;
 mod country_finder;
 mod hierarchy_builder;
+mod low_memory;
 mod mutable_slice;
+pub mod poly;
+mod reference_country_finder;
 mod utils;
 pub mod zone;
 pub mod zone_typer;
@@ -30,7 +35,9 @@ use failure::Error;
 use failure::ResultExt;
 use hierarchy_builder::{build_hierarchy, find_inclusions};
 use mutable_slice::MutableSlice;
-use osmpbfreader::{OsmObj, OsmPbfReader};
+use osmpbfreader::{OsmId, OsmObj, OsmPbfReader};
+use rayon::prelude::*;
+use reference_country_finder::ReferenceCountryFinder;
 use std::collections::BTreeMap;
 use std::fs::File;
 use std::path::{Path, PathBuf};
@@ -51,6 +58,57 @@ pub fn is_admin(obj: &OsmObj) -> bool {
     }
 }
 
+// Implemented by anything carrying a `ZoneIndex` slot, so
+// `reassign_indices_in_order` can be unit tested without building a real
+// `zone::Zone` (which needs a full OSM relation and its geometry).
+trait HasZoneIndex {
+    fn set_zone_index(&mut self, index: ZoneIndex);
+}
+
+impl HasZoneIndex for zone::Zone {
+    fn set_zone_index(&mut self, index: ZoneIndex) {
+        self.id = index;
+    }
+}
+
+// Assigns indices by final position in `items`, overwriting whatever
+// placeholder index each item was given while it was built in parallel, so
+// ordering stays deterministic regardless of how rayon scheduled the work.
+fn reassign_indices_in_order<T: HasZoneIndex>(items: &mut [T]) {
+    for (i, item) in items.iter_mut().enumerate() {
+        item.set_zone_index(ZoneIndex { index: i });
+    }
+}
+
+/// Builds zones from a map of relations and their dependencies, however that map was
+/// assembled. Building a zone's geometry (ring stitching, polygon building) is the
+/// expensive part and is independent per relation, so it's done in parallel. The
+/// `ZoneIndex` each zone is given here is only a placeholder; we assign the real,
+/// deterministic indices afterwards so that ordering (and thus `find_inclusions` /
+/// `build_hierarchy`) stays stable regardless of how rayon schedules the work.
+pub(crate) fn build_zones_from_objects(objects: &BTreeMap<OsmId, OsmObj>) -> Vec<zone::Zone> {
+    let mut zones: Vec<zone::Zone> = objects
+        .values()
+        .filter(|obj| is_admin(obj))
+        .collect::<Vec<_>>()
+        .into_par_iter()
+        .filter_map(|obj| {
+            if let OsmObj::Relation(ref relation) = *obj {
+                let placeholder_index = ZoneIndex { index: 0 };
+                zone::Zone::from_osm_with_geom(relation, objects, placeholder_index)
+            } else {
+                None
+            }
+        })
+        // Ignore zone without boundary polygon for the moment
+        .filter(|zone| zone.boundary.is_some())
+        .collect();
+
+    reassign_indices_in_order(&mut zones);
+
+    zones
+}
+
 pub fn get_zones_and_stats(
     pbf: &mut OsmPbfReader<File>,
 ) -> Result<(Vec<zone::Zone>, CosmogonyStats), Error> {
@@ -59,25 +117,9 @@ pub fn get_zones_and_stats(
         .context("invalid osm file")?;
     info!("reading pbf done.");
 
-    let mut zones = vec![];
-    let stats = CosmogonyStats::default();
-
-    for obj in objects.values() {
-        if !is_admin(obj) {
-            continue;
-        }
-        if let OsmObj::Relation(ref relation) = *obj {
-            let next_index = ZoneIndex { index: zones.len() };
-            if let Some(zone) = zone::Zone::from_osm_with_geom(relation, &objects, next_index) {
-                // Ignore zone without boundary polygon for the moment
-                if zone.boundary.is_some() {
-                    zones.push(zone);
-                }
-            };
-        }
-    }
+    let zones = build_zones_from_objects(&objects);
 
-    return Ok((zones, stats));
+    Ok((zones, CosmogonyStats::default()))
 }
 
 pub fn get_zones_and_stats_without_geom(
@@ -105,14 +147,18 @@ pub fn get_zones_and_stats_without_geom(
 
 fn get_country_code<'a>(
     country_finder: &'a CountryFinder,
+    reference_country_finder: &'a Option<ReferenceCountryFinder>,
     zone: &zone::Zone,
     country_code: &'a Option<String>,
 ) -> Option<String> {
     if let &Some(ref c) = country_code {
-        Some(c.clone())
-    } else {
-        country_finder.find_zone_country(&zone)
+        return Some(c.clone());
     }
+    country_finder.find_zone_country(&zone).or_else(|| {
+        reference_country_finder
+            .as_ref()
+            .and_then(|finder| finder.find_zone_country(&zone))
+    })
 }
 
 fn type_zones(
@@ -120,18 +166,28 @@ fn type_zones(
     stats: &mut CosmogonyStats,
     libpostal_file_path: PathBuf,
     country_code: Option<String>,
+    reference_country_finder: &Option<ReferenceCountryFinder>,
     inclusions: &Vec<Vec<ZoneIndex>>,
 ) -> Result<(), Error> {
     let zone_typer = zone_typer::ZoneTyper::new(libpostal_file_path)?;
     let country_finder: CountryFinder = zones.iter().collect();
-    if country_code.is_none() && country_finder.is_empty() {
+    let no_country_available = country_finder.is_empty()
+        && reference_country_finder
+            .as_ref()
+            .map_or(true, |finder| finder.is_empty());
+    if country_code.is_none() && no_country_available {
         return Err(failure::err_msg(
             "no country_code has been provided and no country have been found, we won't be able to make a cosmogony",
         ));
     }
 
     for i in 0..zones.len() {
-        let country_code = get_country_code(&country_finder, &zones[i], &country_code);
+        let country_code = get_country_code(
+            &country_finder,
+            reference_country_finder,
+            &zones[i],
+            &country_code,
+        );
         match country_code {
             None => {
                 info!(
@@ -187,10 +243,18 @@ fn create_ontology(
     stats: &mut CosmogonyStats,
     libpostal_file_path: PathBuf,
     country_code: Option<String>,
+    reference_country_finder: &Option<ReferenceCountryFinder>,
 ) -> Result<(), Error> {
     let inclusions = find_inclusions(zones);
 
-    type_zones(zones, stats, libpostal_file_path, country_code, &inclusions)?;
+    type_zones(
+        zones,
+        stats,
+        libpostal_file_path,
+        country_code,
+        reference_country_finder,
+        &inclusions,
+    )?;
 
     build_hierarchy(zones, inclusions);
 
@@ -208,21 +272,37 @@ fn create_ontology(
 pub fn build_cosmogony(
     pbf_path: String,
     with_geom: bool,
+    low_memory: bool,
     libpostal_file_path: PathBuf,
     country_code: Option<String>,
+    reference_countries_file_path: Option<PathBuf>,
+    poly_files_dir: Option<PathBuf>,
 ) -> Result<Cosmogony, Error> {
     let path = Path::new(&pbf_path);
     let file = File::open(&path).context("no pbf file")?;
 
     let mut parsed_pbf = OsmPbfReader::new(file);
 
-    let (mut zones, mut stats) = if with_geom {
-        get_zones_and_stats(&mut parsed_pbf)?
-    } else {
+    let (mut zones, mut stats) = if !with_geom {
         get_zones_and_stats_without_geom(&mut parsed_pbf)?
+    } else if low_memory {
+        low_memory::get_zones_and_stats(&mut parsed_pbf)?
+    } else {
+        get_zones_and_stats(&mut parsed_pbf)?
     };
 
-    create_ontology(&mut zones, &mut stats, libpostal_file_path, country_code)?;
+    let reference_country_finder = reference_countries_file_path
+        .map(|p| ReferenceCountryFinder::new(&p))
+        .transpose()
+        .context("invalid reference countries file")?;
+
+    create_ontology(
+        &mut zones,
+        &mut stats,
+        libpostal_file_path,
+        country_code,
+        &reference_country_finder,
+    )?;
 
     stats.compute(&zones);
 
@@ -236,5 +316,43 @@ pub fn build_cosmogony(
             stats: stats,
         },
     };
+
+    if let Some(dir) = poly_files_dir {
+        poly::write_poly_files(&cosmogony, &dir).context("unable to write poly files")?;
+    }
+
     Ok(cosmogony)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct StubZone {
+        id: ZoneIndex,
+    }
+
+    impl HasZoneIndex for StubZone {
+        fn set_zone_index(&mut self, index: ZoneIndex) {
+            self.id = index;
+        }
+    }
+
+    #[test]
+    fn reassign_indices_in_order_matches_position_not_the_placeholder_id() {
+        // mimics zones coming out of the parallel build step: every one
+        // still carries whatever placeholder index it happened to be given,
+        // not its final position.
+        let mut zones = vec![
+            StubZone { id: ZoneIndex { index: 42 } },
+            StubZone { id: ZoneIndex { index: 7 } },
+            StubZone { id: ZoneIndex { index: 100 } },
+        ];
+
+        reassign_indices_in_order(&mut zones);
+
+        assert_eq!(zones[0].id.index, 0);
+        assert_eq!(zones[1].id.index, 1);
+        assert_eq!(zones[2].id.index, 2);
+    }
+}
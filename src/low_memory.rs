@@ -0,0 +1,256 @@
+//! Memory-bounded alternative to `get_objs_and_deps`, for continent/planet
+//! scale PBFs where materializing every admin relation plus all
+//! transitively referenced ways and nodes into a single `BTreeMap` in one
+//! go (as `get_zones_and_stats` does) is infeasible.
+//!
+//! Instead of loading the full dependency closure in a single pass, this
+//! streams through the file three times, each pass narrower than the last:
+//!
+//! * the first pass only looks at relations, remembering each one's member
+//!   ids (not its tags or geometry). From that we compute, in memory, the
+//!   transitive closure of relation and way ids an admin relation actually
+//!   needs, following nested relation members the same way
+//!   `get_objs_and_deps` does. Way and node objects are never retained
+//!   during this pass;
+//! * the second pass only looks at ways, and only records the node ids of
+//!   ways that ended up in that closure - not every way in the file, so
+//!   this buffer scales with the admin relations' own geometry instead of
+//!   the whole planet's way/node graph;
+//! * the third pass re-reads the file keeping only the objects whose id
+//!   ended up in the now-complete closure, so the resulting map only holds
+//!   what's actually required instead of everything. The map is a
+//!   `BTreeMap`, so it is not pre-sized: it grows as each required object
+//!   is inserted.
+//!
+//! This trades extra IO (the file is read three times) for a peak memory
+//! footprint that scales with the admin relations' own dependency closure
+//! rather than with the size of the file, unlike `get_zones_and_stats`,
+//! which keeps every dependency's full object around for the whole run.
+//!
+//! Note: each pass still goes through `OsmPbfReader::iter`, so objects
+//! outside of what a pass cares about are still decoded into an `OsmObj`
+//! before being discarded; only the *retained* memory is bounded by the
+//! closure, not the per-pass decode cost.
+
+use build_zones_from_objects;
+use cosmogony::CosmogonyStats;
+use failure::{Error, ResultExt};
+use is_admin;
+use osmpbfreader::{NodeId, OsmId, OsmObj, OsmPbfReader, RelationId, WayId};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use zone;
+
+struct RelationInfo {
+    is_admin: bool,
+    refs: Vec<OsmId>,
+}
+
+struct RequiredIds {
+    relation_ids: HashSet<RelationId>,
+    way_ids: HashSet<WayId>,
+    node_ids: HashSet<NodeId>,
+}
+
+// Pass 1: relations only. Ways and nodes are matched away immediately, so
+// the only thing retained across the whole file is relation member lists,
+// which are orders of magnitude smaller than the ways/nodes graph they end
+// up referencing.
+fn collect_relations(pbf: &mut OsmPbfReader<File>) -> Result<BTreeMap<RelationId, RelationInfo>, Error> {
+    let mut relations = BTreeMap::new();
+
+    for obj in pbf.iter() {
+        let obj = obj.context("invalid osm object while scanning relations")?;
+        let is_admin_relation = is_admin(&obj);
+        if let OsmObj::Relation(relation) = obj {
+            relations.insert(
+                relation.id,
+                RelationInfo {
+                    is_admin: is_admin_relation,
+                    refs: relation.refs.into_iter().map(|refe| refe.member).collect(),
+                },
+            );
+        }
+    }
+
+    Ok(relations)
+}
+
+// Walks the member graph starting from the admin relations, the same way
+// `get_objs_and_deps` resolves dependencies transitively regardless of
+// member type, so a nested relation-in-relation boundary ends up with the
+// same (complete) geometry in low-memory mode as in the default path. Node
+// ids directly referenced by a relation (e.g. an `admin_centre` member) are
+// collected here too; node ids reached through a way are filled in by
+// `collect_required_node_ids` once we know which ways matter.
+fn required_relations_and_ways(
+    relations: &BTreeMap<RelationId, RelationInfo>,
+) -> (HashSet<RelationId>, HashSet<WayId>, HashSet<NodeId>) {
+    let mut relation_ids = HashSet::new();
+    let mut way_ids = HashSet::new();
+    let mut direct_node_ids = HashSet::new();
+
+    let mut to_visit: Vec<RelationId> = relations
+        .iter()
+        .filter(|&(_, info)| info.is_admin)
+        .map(|(&id, _)| id)
+        .collect();
+
+    while let Some(relation_id) = to_visit.pop() {
+        if !relation_ids.insert(relation_id) {
+            continue; // already visited, guards against relation member cycles
+        }
+
+        let info = match relations.get(&relation_id) {
+            Some(info) => info,
+            None => continue,
+        };
+
+        for member in &info.refs {
+            match *member {
+                OsmId::Way(id) => {
+                    way_ids.insert(id);
+                }
+                OsmId::Node(id) => {
+                    direct_node_ids.insert(id);
+                }
+                OsmId::Relation(id) => to_visit.push(id),
+            }
+        }
+    }
+
+    (relation_ids, way_ids, direct_node_ids)
+}
+
+// Pass 2: ways only, and only the ones in `way_ids` - everything else is
+// matched away without its node list ever being retained.
+fn collect_required_node_ids(
+    pbf: &mut OsmPbfReader<File>,
+    way_ids: &HashSet<WayId>,
+) -> Result<HashSet<NodeId>, Error> {
+    let mut node_ids = HashSet::new();
+
+    for obj in pbf.iter() {
+        let obj = obj.context("invalid osm object while scanning required ways")?;
+        if let OsmObj::Way(way) = obj {
+            if way_ids.contains(&way.id) {
+                node_ids.extend(way.nodes.into_iter());
+            }
+        }
+    }
+
+    Ok(node_ids)
+}
+
+/// Low memory variant of `get_zones_and_stats`: makes three narrowing
+/// streaming passes over the pbf so the in-memory object map only ever
+/// holds the relations, ways and nodes that admin relations (and their
+/// nested members) actually need.
+pub fn get_zones_and_stats(
+    pbf: &mut OsmPbfReader<File>,
+) -> Result<(Vec<zone::Zone>, CosmogonyStats), Error> {
+    info!("Reading pbf in low memory mode (pass 1/3: scanning relations)...");
+    let relations = collect_relations(pbf)?;
+    let (relation_ids, way_ids, direct_node_ids) = required_relations_and_ways(&relations);
+    drop(relations);
+    pbf.rewind().context("unable to rewind pbf reader")?;
+
+    info!("Reading pbf in low memory mode (pass 2/3: scanning required ways)...");
+    let mut node_ids = collect_required_node_ids(pbf, &way_ids)?;
+    node_ids.extend(direct_node_ids);
+    pbf.rewind().context("unable to rewind pbf reader")?;
+
+    let required = RequiredIds {
+        relation_ids,
+        way_ids,
+        node_ids,
+    };
+
+    info!("Reading pbf in low memory mode (pass 3/3: loading required objects)...");
+    let mut objects = BTreeMap::new();
+    for obj in pbf.iter() {
+        let obj = obj.context("invalid osm object while loading required objects")?;
+        let is_required = match obj.id() {
+            OsmId::Node(id) => required.node_ids.contains(&id),
+            OsmId::Way(id) => required.way_ids.contains(&id),
+            OsmId::Relation(id) => required.relation_ids.contains(&id),
+        };
+        if is_required {
+            objects.insert(obj.id(), obj);
+        }
+    }
+    info!("reading pbf done.");
+
+    let zones = build_zones_from_objects(&objects);
+
+    Ok((zones, CosmogonyStats::default()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn relation_info(is_admin: bool, refs: Vec<OsmId>) -> RelationInfo {
+        RelationInfo { is_admin, refs }
+    }
+
+    #[test]
+    fn required_relations_and_ways_follows_nested_relation_members() {
+        // admin relation #1 has a sub-relation #2 as a member, which in turn
+        // references way #10; #1 also directly references way #11.
+        let mut relations = BTreeMap::new();
+        relations.insert(
+            RelationId(1),
+            relation_info(
+                true,
+                vec![OsmId::Relation(RelationId(2)), OsmId::Way(WayId(11))],
+            ),
+        );
+        relations.insert(RelationId(2), relation_info(false, vec![OsmId::Way(WayId(10))]));
+
+        let (relation_ids, way_ids, direct_node_ids) = required_relations_and_ways(&relations);
+
+        assert!(relation_ids.contains(&RelationId(1)));
+        assert!(relation_ids.contains(&RelationId(2)));
+        assert!(way_ids.contains(&WayId(10)));
+        assert!(way_ids.contains(&WayId(11)));
+        assert!(direct_node_ids.is_empty());
+    }
+
+    #[test]
+    fn required_relations_and_ways_collects_direct_node_members() {
+        let mut relations = BTreeMap::new();
+        relations.insert(
+            RelationId(1),
+            relation_info(true, vec![OsmId::Node(NodeId(42))]),
+        );
+
+        let (_, _, direct_node_ids) = required_relations_and_ways(&relations);
+
+        assert!(direct_node_ids.contains(&NodeId(42)));
+    }
+
+    #[test]
+    fn required_relations_and_ways_ignores_non_admin_relations_not_reachable_from_an_admin_one() {
+        let mut relations = BTreeMap::new();
+        relations.insert(RelationId(1), relation_info(true, vec![OsmId::Way(WayId(1))]));
+        relations.insert(RelationId(2), relation_info(false, vec![OsmId::Way(WayId(2))]));
+
+        let (relation_ids, way_ids, _) = required_relations_and_ways(&relations);
+
+        assert!(!relation_ids.contains(&RelationId(2)));
+        assert!(!way_ids.contains(&WayId(2)));
+    }
+
+    #[test]
+    fn required_relations_and_ways_handles_relation_member_cycles() {
+        // #1 <-> #2 reference each other; must not loop forever.
+        let mut relations = BTreeMap::new();
+        relations.insert(RelationId(1), relation_info(true, vec![OsmId::Relation(RelationId(2))]));
+        relations.insert(RelationId(2), relation_info(false, vec![OsmId::Relation(RelationId(1))]));
+
+        let (relation_ids, _, _) = required_relations_and_ways(&relations);
+
+        assert_eq!(relation_ids.len(), 2);
+    }
+}
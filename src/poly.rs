@@ -0,0 +1,103 @@
+//! Export of zone boundaries as Osmosis `.poly` files.
+//!
+//! The `.poly` format is documented at
+//! https://wiki.openstreetmap.org/wiki/Osmosis/Polygon_Filter_File_Format
+//! and is commonly consumed by region-based OSM extraction tools.
+
+use failure::{Error, ResultExt};
+use geo::{LineString, MultiPolygon};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use cosmogony::Cosmogony;
+use zone::Zone;
+
+/// Writes one `.poly` file per zone of `cosmogony` into `dir`, named after
+/// the zone's osm id. Zones without a boundary are skipped.
+pub fn write_poly_files(cosmogony: &Cosmogony, dir: &Path) -> Result<(), Error> {
+    for zone in &cosmogony.zones {
+        if let Some(ref boundary) = zone.boundary {
+            write_poly_file(zone, boundary, dir)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_poly_file(zone: &Zone, boundary: &MultiPolygon<f64>, dir: &Path) -> Result<(), Error> {
+    let file_name = format!("{}.poly", sanitize_osm_id(&zone.osm_id));
+    let path = dir.join(&file_name);
+    let file = File::create(&path).context(format!("unable to create poly file {:?}", path))?;
+    let mut writer = BufWriter::new(file);
+
+    writeln!(writer, "{}", zone.osm_id)?;
+
+    let mut section = 1;
+    for polygon in &boundary.0 {
+        write_ring(&mut writer, &section.to_string(), polygon.exterior())?;
+        section += 1;
+
+        for interior in polygon.interiors() {
+            write_ring(&mut writer, &format!("!{}", section), interior)?;
+            section += 1;
+        }
+    }
+
+    writeln!(writer, "END")?;
+    Ok(())
+}
+
+fn write_ring<W: Write>(writer: &mut W, name: &str, ring: &LineString<f64>) -> Result<(), Error> {
+    writeln!(writer, "{}", name)?;
+    for point in ring.points_iter() {
+        writeln!(writer, "    {:.7} {:.7}", point.x(), point.y())?;
+    }
+    writeln!(writer, "END")?;
+    Ok(())
+}
+
+fn sanitize_osm_id(osm_id: &str) -> String {
+    osm_id.replace(':', "_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geo::Coordinate;
+
+    #[test]
+    fn sanitize_osm_id_replaces_colons() {
+        assert_eq!(sanitize_osm_id("relation:123456"), "relation_123456");
+        assert_eq!(sanitize_osm_id("123456"), "123456");
+    }
+
+    #[test]
+    fn write_ring_formats_an_outer_ring() {
+        let ring: LineString<f64> = vec![
+            Coordinate { x: 2.5, y: 48.85 },
+            Coordinate { x: 2.6, y: 48.9 },
+            Coordinate { x: 2.5, y: 48.85 },
+        ].into();
+
+        let mut output = Vec::new();
+        write_ring(&mut output, "1", &ring).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "1\n    2.5000000 48.8500000\n    2.6000000 48.9000000\n    2.5000000 48.8500000\nEND\n"
+        );
+    }
+
+    #[test]
+    fn write_ring_writes_a_hole_name_as_given() {
+        let ring: LineString<f64> = vec![
+            Coordinate { x: 0.0, y: 0.0 },
+            Coordinate { x: 1.0, y: 1.0 },
+        ].into();
+
+        let mut output = Vec::new();
+        write_ring(&mut output, "!2", &ring).unwrap();
+
+        assert!(String::from_utf8(output).unwrap().starts_with("!2\n"));
+    }
+}
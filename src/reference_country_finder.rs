@@ -0,0 +1,298 @@
+//! Fallback country lookup based on externally supplied reference country
+//! boundaries, for use when an OSM extract doesn't contain enough
+//! admin_level=2 relations for `CountryFinder` to be built from the data
+//! itself (e.g. a city-only or region-only extract). This mirrors the way
+//! border-generation tooling loads precomputed country borders
+//! independently of the OSM data being processed.
+//!
+//! Only GeoJSON reference files are supported for now; a `.kml` file is
+//! rejected up front with an explicit error rather than failing the
+//! GeoJSON parser with a confusing message.
+
+use failure::{Error, ResultExt};
+use geo::algorithm::contains::Contains;
+use geo::{MultiPolygon, Point, Polygon};
+use geojson::conversion::TryInto;
+use geojson::{GeoJson, Value};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use utils::representative_point;
+use zone::Zone;
+
+/// GeoJSON property expected to carry the ISO country code of each
+/// reference feature.
+const COUNTRY_CODE_PROPERTY: &str = "ISO3166-1:alpha2";
+
+/// Size, in degrees, of a cell of the coarse spatial grid `ReferenceCountryFinder`
+/// indexes its polygons by. Countries span a wide range of sizes but are never
+/// tiny, so a coarse grid is enough to keep a query from scanning every polygon.
+const GRID_CELL_SIZE: f64 = 10.0;
+
+type GridCell = (i32, i32);
+
+#[derive(Clone, Copy)]
+struct BoundingBox {
+    min_x: f64,
+    min_y: f64,
+    max_x: f64,
+    max_y: f64,
+}
+
+impl BoundingBox {
+    fn of(multi_polygon: &MultiPolygon<f64>) -> Option<BoundingBox> {
+        multi_polygon
+            .0
+            .iter()
+            .flat_map(|polygon| polygon.exterior().points_iter())
+            .fold(None, |bbox, point| {
+                Some(match bbox {
+                    None => BoundingBox {
+                        min_x: point.x(),
+                        min_y: point.y(),
+                        max_x: point.x(),
+                        max_y: point.y(),
+                    },
+                    Some(b) => BoundingBox {
+                        min_x: b.min_x.min(point.x()),
+                        min_y: b.min_y.min(point.y()),
+                        max_x: b.max_x.max(point.x()),
+                        max_y: b.max_y.max(point.y()),
+                    },
+                })
+            })
+    }
+
+    fn contains(&self, point: &Point<f64>) -> bool {
+        point.x() >= self.min_x && point.x() <= self.max_x && point.y() >= self.min_y
+            && point.y() <= self.max_y
+    }
+
+    fn cells(&self) -> impl Iterator<Item = GridCell> {
+        let (min_cx, min_cy) = grid_cell(self.min_x, self.min_y);
+        let (max_cx, max_cy) = grid_cell(self.max_x, self.max_y);
+        (min_cx..=max_cx).flat_map(move |cx| (min_cy..=max_cy).map(move |cy| (cx, cy)))
+    }
+}
+
+fn grid_cell(x: f64, y: f64) -> GridCell {
+    ((x / GRID_CELL_SIZE).floor() as i32, (y / GRID_CELL_SIZE).floor() as i32)
+}
+
+struct ReferenceCountry {
+    country_code: String,
+    boundary: MultiPolygon<f64>,
+    bbox: BoundingBox,
+}
+
+/// An indexed point-in-polygon lookup over a set of reference country
+/// polygons, loaded once from an external GeoJSON file. Polygons are
+/// indexed by a coarse spatial grid over their bounding box, so a query
+/// only tests the (small) set of countries whose cell could possibly
+/// contain the point instead of scanning every polygon.
+pub struct ReferenceCountryFinder {
+    countries: Vec<ReferenceCountry>,
+    grid: BTreeMap<GridCell, Vec<usize>>,
+}
+
+impl ReferenceCountryFinder {
+    pub fn new(path: &Path) -> Result<Self, Error> {
+        if path.extension().map_or(false, |ext| ext == "kml") {
+            bail!(
+                "{:?} looks like a KML file, but only GeoJSON reference countries files are supported",
+                path
+            );
+        }
+
+        let mut content = String::new();
+        File::open(path)
+            .context(format!("unable to open reference countries file {:?}", path))?
+            .read_to_string(&mut content)
+            .context(format!("unable to read reference countries file {:?}", path))?;
+
+        let geojson: GeoJson = content
+            .parse()
+            .context("invalid reference countries geojson")?;
+
+        let collection = match geojson {
+            GeoJson::FeatureCollection(collection) => collection,
+            _ => bail!("reference countries geojson is not a FeatureCollection"),
+        };
+
+        let countries: Vec<ReferenceCountry> = collection
+            .features
+            .into_iter()
+            .filter_map(|feature| {
+                let country_code = feature
+                    .properties
+                    .as_ref()?
+                    .get(COUNTRY_CODE_PROPERTY)?
+                    .as_str()?
+                    .to_string();
+                let boundary = to_multi_polygon(feature.geometry?.value)?;
+                let bbox = BoundingBox::of(&boundary)?;
+
+                Some(ReferenceCountry {
+                    country_code,
+                    boundary,
+                    bbox,
+                })
+            })
+            .collect();
+
+        let mut grid: BTreeMap<GridCell, Vec<usize>> = BTreeMap::new();
+        for (index, country) in countries.iter().enumerate() {
+            for cell in country.bbox.cells() {
+                grid.entry(cell).or_insert_with(Vec::new).push(index);
+            }
+        }
+
+        Ok(ReferenceCountryFinder { countries, grid })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.countries.is_empty()
+    }
+
+    /// Tests a representative point of `zone` (guaranteed to lie inside its
+    /// boundary, unlike a centroid) against the reference polygons whose
+    /// grid cell it falls into, returning the country code of the first one
+    /// that actually contains it.
+    pub fn find_zone_country(&self, zone: &Zone) -> Option<String> {
+        let point = representative_point(zone.boundary.as_ref()?)?;
+        let candidates = self.grid.get(&grid_cell(point.x(), point.y()))?;
+
+        candidates
+            .iter()
+            .map(|&index| &self.countries[index])
+            .find(|country| country.bbox.contains(&point) && country.boundary.contains(&point))
+            .map(|country| country.country_code.clone())
+    }
+}
+
+fn to_multi_polygon(value: Value) -> Option<MultiPolygon<f64>> {
+    match value {
+        v @ Value::Polygon(_) => {
+            let polygon: Polygon<f64> = v.try_into().ok()?;
+            Some(MultiPolygon(vec![polygon]))
+        }
+        v @ Value::MultiPolygon(_) => v.try_into().ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn square() -> Vec<Vec<Vec<f64>>> {
+        vec![vec![
+            vec![0.0, 0.0],
+            vec![1.0, 0.0],
+            vec![1.0, 1.0],
+            vec![0.0, 1.0],
+            vec![0.0, 0.0],
+        ]]
+    }
+
+    fn far_away_square() -> Vec<Vec<Vec<f64>>> {
+        vec![vec![
+            vec![80.0, 80.0],
+            vec![80.2, 80.0],
+            vec![80.2, 80.2],
+            vec![80.0, 80.2],
+            vec![80.0, 80.0],
+        ]]
+    }
+
+    #[test]
+    fn to_multi_polygon_accepts_a_polygon() {
+        let value = Value::Polygon(square());
+
+        let multi_polygon = to_multi_polygon(value).expect("a polygon should convert");
+
+        assert_eq!(multi_polygon.0.len(), 1);
+    }
+
+    #[test]
+    fn to_multi_polygon_accepts_a_multi_polygon() {
+        let value = Value::MultiPolygon(vec![square(), square()]);
+
+        let multi_polygon = to_multi_polygon(value).expect("a multi polygon should convert");
+
+        assert_eq!(multi_polygon.0.len(), 2);
+    }
+
+    #[test]
+    fn to_multi_polygon_rejects_unrelated_geometries() {
+        let value = Value::Point(vec![0.0, 0.0]);
+
+        assert!(to_multi_polygon(value).is_none());
+    }
+
+    #[test]
+    fn bounding_box_of_tracks_the_extent_of_every_part() {
+        let value = Value::MultiPolygon(vec![square(), far_away_square()]);
+        let multi_polygon = to_multi_polygon(value).unwrap();
+
+        let bbox = BoundingBox::of(&multi_polygon).expect("a bbox should be computed");
+
+        assert_eq!(bbox.min_x, 0.0);
+        assert_eq!(bbox.min_y, 0.0);
+        assert_eq!(bbox.max_x, 80.2);
+        assert_eq!(bbox.max_y, 80.2);
+    }
+
+    #[test]
+    fn bounding_box_contains_tests_only_the_box_not_the_shape() {
+        let multi_polygon = to_multi_polygon(Value::Polygon(square())).unwrap();
+        let bbox = BoundingBox::of(&multi_polygon).unwrap();
+
+        assert!(bbox.contains(&Point::new(0.5, 0.5)));
+        assert!(!bbox.contains(&Point::new(5.0, 5.0)));
+    }
+
+    #[test]
+    fn bounding_box_cells_spans_every_grid_cell_it_overlaps() {
+        let multi_polygon = to_multi_polygon(Value::Polygon(square())).unwrap();
+        let bbox = BoundingBox::of(&multi_polygon).unwrap();
+
+        let cells: Vec<GridCell> = bbox.cells().collect();
+
+        // a unit square sits entirely within a single GRID_CELL_SIZE cell.
+        assert_eq!(cells, vec![(0, 0)]);
+    }
+
+    #[test]
+    fn bounding_box_cells_differ_for_countries_far_apart() {
+        let mut far = far_away_square();
+        // shift it even further from the unit square, so the two countries
+        // are far apart and can't share a grid cell.
+        for ring in &mut far {
+            for coord in ring.iter_mut() {
+                coord[0] += 80.0;
+                coord[1] += 80.0;
+            }
+        }
+        let near_bbox = BoundingBox::of(&to_multi_polygon(Value::Polygon(square())).unwrap()).unwrap();
+        let far_bbox = BoundingBox::of(&to_multi_polygon(Value::Polygon(far.clone())).unwrap()).unwrap();
+
+        assert_ne!(near_bbox.cells().collect::<Vec<_>>(), far_bbox.cells().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn new_rejects_kml_files_up_front() {
+        let result = ReferenceCountryFinder::new(Path::new("/tmp/whatever.kml"));
+
+        assert!(result.is_err());
+        assert!(
+            result
+                .unwrap_err()
+                .to_string()
+                .to_lowercase()
+                .contains("kml")
+        );
+    }
+}
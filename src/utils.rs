@@ -0,0 +1,152 @@
+//! Small geometric helpers shared by the country-lookup code.
+
+use geo::{LineString, MultiPolygon, Point, Polygon};
+use std::cmp::Ordering;
+
+/// Finds a point that is guaranteed to lie in the interior of `multi_polygon`
+/// (the equivalent of PostGIS `ST_PointOnSurface` / Shapely's
+/// `representative_point`). Unlike a geometric centroid, this can't fall
+/// outside the shape, which matters for the non-convex, multi-part
+/// geometries real administrative and country boundaries tend to have
+/// (archipelagos, crescents, exclaves...).
+pub(crate) fn representative_point(multi_polygon: &MultiPolygon<f64>) -> Option<Point<f64>> {
+    multi_polygon
+        .0
+        .iter()
+        .filter_map(polygon_representative_point)
+        .max_by(|&(_, a_width), &(_, b_width)| {
+            a_width.partial_cmp(&b_width).unwrap_or(Ordering::Equal)
+        })
+        .map(|(point, _width)| point)
+}
+
+// Scans the polygon with a handful of horizontal lines, picks the widest
+// interior span found (respecting holes via the even-odd rule), and returns
+// its midpoint along with its width, so callers can compare candidates
+// across a MultiPolygon's parts.
+fn polygon_representative_point(polygon: &Polygon<f64>) -> Option<(Point<f64>, f64)> {
+    let mut rings: Vec<&LineString<f64>> = vec![polygon.exterior()];
+    rings.extend(polygon.interiors());
+
+    let mut ys: Vec<f64> = rings
+        .iter()
+        .flat_map(|ring| ring.points_iter().map(|p| p.y()))
+        .collect();
+    ys.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+    ys.dedup();
+
+    ys.windows(2)
+        .filter_map(|window| {
+            let y = (window[0] + window[1]) / 2.0;
+            widest_span_at(&rings, y).map(|(x, width)| (Point::new(x, y), width))
+        })
+        .max_by(|&(_, a_width), &(_, b_width)| {
+            a_width.partial_cmp(&b_width).unwrap_or(Ordering::Equal)
+        })
+}
+
+fn widest_span_at(rings: &[&LineString<f64>], y: f64) -> Option<(f64, f64)> {
+    let mut xs: Vec<f64> = rings
+        .iter()
+        .flat_map(|ring| scanline_intersections(ring, y))
+        .collect();
+    xs.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+
+    xs.chunks(2)
+        .filter_map(|pair| match pair {
+            [start, end] => Some(((start + end) / 2.0, end - start)),
+            _ => None,
+        })
+        .max_by(|&(_, a_width), &(_, b_width)| {
+            a_width.partial_cmp(&b_width).unwrap_or(Ordering::Equal)
+        })
+}
+
+fn scanline_intersections(ring: &LineString<f64>, y: f64) -> Vec<f64> {
+    ring.0
+        .windows(2)
+        .filter_map(|edge| {
+            let (p1, p2) = (edge[0], edge[1]);
+            if (p1.y <= y && p2.y > y) || (p2.y <= y && p1.y > y) {
+                let t = (y - p1.y) / (p2.y - p1.y);
+                Some(p1.x + t * (p2.x - p1.x))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geo::{Coordinate, Polygon};
+
+    fn ring(coords: &[(f64, f64)]) -> LineString<f64> {
+        coords
+            .iter()
+            .map(|&(x, y)| Coordinate { x, y })
+            .collect()
+    }
+
+    #[test]
+    fn representative_point_is_inside_a_square_with_a_hole() {
+        // A square frame (outer square with a centred square hole cut out
+        // of it): the centroid of the outer square alone would land inside
+        // the hole, which is exactly the failure mode this helper avoids.
+        let outer = ring(&[
+            (0.0, 0.0),
+            (10.0, 0.0),
+            (10.0, 10.0),
+            (0.0, 10.0),
+            (0.0, 0.0),
+        ]);
+        let hole = ring(&[
+            (1.0, 1.0),
+            (9.0, 1.0),
+            (9.0, 9.0),
+            (1.0, 9.0),
+            (1.0, 1.0),
+        ]);
+        let polygon = Polygon::new(outer, vec![hole]);
+        let multi_polygon = MultiPolygon(vec![polygon]);
+
+        let point = representative_point(&multi_polygon).expect("a point should be found");
+
+        // centroid of the outer ring (5, 5) is inside the hole: make sure we
+        // didn't just return that.
+        assert!(!(point.x() > 1.0 && point.x() < 9.0 && point.y() > 1.0 && point.y() < 9.0));
+        assert!(point.x() >= 0.0 && point.x() <= 10.0);
+        assert!(point.y() >= 0.0 && point.y() <= 10.0);
+    }
+
+    #[test]
+    fn representative_point_picks_the_larger_part_of_an_exclave() {
+        let mainland = Polygon::new(
+            ring(&[
+                (0.0, 0.0),
+                (10.0, 0.0),
+                (10.0, 10.0),
+                (0.0, 10.0),
+                (0.0, 0.0),
+            ]),
+            vec![],
+        );
+        let tiny_exclave = Polygon::new(
+            ring(&[
+                (100.0, 100.0),
+                (100.1, 100.0),
+                (100.1, 100.1),
+                (100.0, 100.1),
+                (100.0, 100.0),
+            ]),
+            vec![],
+        );
+        let multi_polygon = MultiPolygon(vec![tiny_exclave, mainland]);
+
+        let point = representative_point(&multi_polygon).expect("a point should be found");
+
+        assert!(point.x() >= 0.0 && point.x() <= 10.0);
+        assert!(point.y() >= 0.0 && point.y() <= 10.0);
+    }
+}